@@ -126,6 +126,187 @@ pub(crate) fn load_inner<R: BufRead + Seek>(r: R, limits: super::Limits, format:
     load_decoder(r, format, LoadVisitor(limits))
 }
 
+/// Create a new image from a Reader, recovering as much of the image as possible instead of
+/// failing on truncated or otherwise corrupt input.
+///
+/// Behaves like [`load`] up until the decoder has determined the image's dimensions and color
+/// type and the output pixel buffer has been allocated. From that point on this function always
+/// returns `Ok`: any decode error is discarded, the undecoded remainder of the buffer is filled
+/// with the pixel type's default value, and the partially decoded `DynamicImage` is returned.
+/// This is useful for displaying truncated downloads or slightly damaged files rather than
+/// getting nothing at all.
+///
+/// [`load`]: fn.load.html
+#[allow(unused_variables)]
+// r is unused if no features are supported.
+pub fn load_lossy<R: BufRead + Seek>(r: R, format: ImageFormat) -> ImageResult<DynamicImage> {
+    load_inner_lossy(r, super::Limits::default(), format)
+}
+
+pub(crate) fn load_inner_lossy<R: BufRead + Seek>(r: R, limits: super::Limits, format: ImageFormat) -> ImageResult<DynamicImage> {
+    struct LoadVisitorLossy(super::Limits);
+
+    impl DecoderVisitor for LoadVisitorLossy {
+        type Result = DynamicImage;
+
+        fn visit_decoder<'a, D: ImageDecoder<'a>>(self, mut decoder: D) -> ImageResult<Self::Result> {
+            let mut limits = self.0;
+            let total_bytes = decoder.total_bytes();
+            // Check that we do not allocate a bigger buffer than we are allowed to
+            if let Some(max_alloc) = limits.max_alloc.as_mut() {
+                if *max_alloc < total_bytes {
+                    return Err(ImageError::Limits(crate::error::LimitError::from_kind(
+                        crate::error::LimitErrorKind::InsufficientMemory)))
+                }
+                // We are allocating a buffer of size `total_bytes` outside of
+                // the decoder. Therefore the decoder gets a smaller limit.
+                *max_alloc -= total_bytes;
+            }
+            decoder.set_limits(self.0)?;
+            DynamicImage::from_decoder_lossy(decoder)
+        }
+    }
+
+    load_decoder(r, format, LoadVisitorLossy(limits))
+}
+
+impl DynamicImage {
+    /// Build a `DynamicImage` from `decoder`, recovering from any decode error that occurs after
+    /// the output buffer has been allocated instead of propagating it. Pairs with [`load_lossy`]
+    /// the way [`DynamicImage::from_decoder`] pairs with [`load`].
+    pub(crate) fn from_decoder_lossy<'a>(decoder: impl ImageDecoder<'a>) -> ImageResult<Self> {
+        let color_type = decoder.color_type();
+        let (width, height) = decoder.dimensions();
+        // The buffer is allocated from here on; any decode error is recovered from instead of
+        // propagated, so this always succeeds. The buffer starts zero-initialized, so a decoder
+        // that errors out partway through simply leaves the undecoded remainder at the pixel
+        // type's zero/default value.
+        let buf = match decoder_to_vec(decoder) {
+            Ok(buf) => buf,
+            Err((buf, _err)) => buf,
+        };
+        dynamic_image_from_raw(color_type, width, height, buf)
+    }
+}
+
+/// Decode `decoder` into a freshly allocated, zero-initialized buffer.
+///
+/// On success the buffer is fully populated. On error the buffer is still returned alongside the
+/// error: it holds whatever the decoder managed to write before failing, with the remainder left
+/// at zero, so a caller that wants to recover from the error (see [`load_lossy`]) has something
+/// to build an image from instead of nothing.
+pub(crate) fn decoder_to_vec<'a, D: ImageDecoder<'a>>(mut decoder: D) -> Result<Vec<u8>, (Vec<u8>, ImageError)> {
+    let mut buf = vec![0u8; decoder.total_bytes() as usize];
+    match decoder.read_image(&mut buf) {
+        Ok(()) => Ok(buf),
+        Err(err) => Err((buf, err)),
+    }
+}
+
+/// Build a `DynamicImage` from a raw, already-decoded pixel buffer for every color type
+/// `ImageDecoder` can report, so [`load_lossy`]'s "always returns `Ok`" contract holds regardless
+/// of which one the source image turns out to use.
+fn dynamic_image_from_raw(color_type: color::ColorType, width: u32, height: u32, buf: Vec<u8>) -> ImageResult<DynamicImage> {
+    let too_small = || ImageError::Unsupported(ImageFormatHint::Unknown.into());
+
+    // `buf` is laid out as native-endian samples of the target type; `decoder_to_vec` never
+    // shrinks it, so a short read just leaves the tail, and thus the tail sample(s) produced
+    // here, at zero.
+    fn samples<T: Copy + Default, const N: usize>(buf: &[u8], from_ne_bytes: fn([u8; N]) -> T) -> Vec<T> {
+        buf.chunks(N)
+            .map(|chunk| {
+                let mut bytes = [0u8; N];
+                bytes[..chunk.len()].copy_from_slice(chunk);
+                from_ne_bytes(bytes)
+            })
+            .collect()
+    }
+
+    match color_type {
+        color::ColorType::L8 => crate::ImageBuffer::from_raw(width, height, buf)
+            .map(DynamicImage::ImageLuma8)
+            .ok_or_else(too_small),
+        color::ColorType::La8 => crate::ImageBuffer::from_raw(width, height, buf)
+            .map(DynamicImage::ImageLumaA8)
+            .ok_or_else(too_small),
+        color::ColorType::Rgb8 => crate::ImageBuffer::from_raw(width, height, buf)
+            .map(DynamicImage::ImageRgb8)
+            .ok_or_else(too_small),
+        color::ColorType::Rgba8 => crate::ImageBuffer::from_raw(width, height, buf)
+            .map(DynamicImage::ImageRgba8)
+            .ok_or_else(too_small),
+        color::ColorType::L16 => crate::ImageBuffer::from_raw(width, height, samples(&buf, u16::from_ne_bytes))
+            .map(DynamicImage::ImageLuma16)
+            .ok_or_else(too_small),
+        color::ColorType::La16 => crate::ImageBuffer::from_raw(width, height, samples(&buf, u16::from_ne_bytes))
+            .map(DynamicImage::ImageLumaA16)
+            .ok_or_else(too_small),
+        color::ColorType::Rgb16 => crate::ImageBuffer::from_raw(width, height, samples(&buf, u16::from_ne_bytes))
+            .map(DynamicImage::ImageRgb16)
+            .ok_or_else(too_small),
+        color::ColorType::Rgba16 => crate::ImageBuffer::from_raw(width, height, samples(&buf, u16::from_ne_bytes))
+            .map(DynamicImage::ImageRgba16)
+            .ok_or_else(too_small),
+        color::ColorType::Rgb32F => crate::ImageBuffer::from_raw(width, height, samples(&buf, f32::from_ne_bytes))
+            .map(DynamicImage::ImageRgb32F)
+            .ok_or_else(too_small),
+        color::ColorType::Rgba32F => crate::ImageBuffer::from_raw(width, height, samples(&buf, f32::from_ne_bytes))
+            .map(DynamicImage::ImageRgba32F)
+            .ok_or_else(too_small),
+        _ => Err(ImageError::Unsupported(ImageFormatHint::Unknown.into())),
+    }
+}
+
+#[cfg(test)]
+mod lossy_decode_tests {
+    use super::*;
+
+    #[test]
+    fn truncated_rgb8_buffer_keeps_decoded_pixels_and_defaults_the_rest() {
+        // Simulates what `load_lossy` sees from a decoder that only wrote the first pixel
+        // before erroring: `decoder_to_vec`'s buffer is zero-initialized up front, so the
+        // undecoded second pixel is already defaulted by the time it reaches this function.
+        let (width, height) = (2, 1);
+        let mut buf = vec![0u8; (width * height * 3) as usize];
+        buf[0] = 10;
+        buf[1] = 20;
+        buf[2] = 30;
+
+        let image = dynamic_image_from_raw(color::ColorType::Rgb8, width, height, buf).unwrap();
+        match image {
+            DynamicImage::ImageRgb8(img) => {
+                assert_eq!(img.get_pixel(0, 0).0, [10, 20, 30]);
+                assert_eq!(img.get_pixel(1, 0).0, [0, 0, 0]);
+            }
+            other => panic!("expected ImageRgb8, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn truncated_rgb32f_buffer_keeps_decoded_pixels_and_defaults_the_rest() {
+        let (width, height) = (2, 1);
+        let mut buf = vec![0u8; (width * height * 3 * 4) as usize];
+        buf[0..4].copy_from_slice(&1.0f32.to_ne_bytes());
+        buf[4..8].copy_from_slice(&0.5f32.to_ne_bytes());
+        buf[8..12].copy_from_slice(&0.25f32.to_ne_bytes());
+
+        let image = dynamic_image_from_raw(color::ColorType::Rgb32F, width, height, buf).unwrap();
+        match image {
+            DynamicImage::ImageRgb32F(img) => {
+                assert_eq!(img.get_pixel(0, 0).0, [1.0, 0.5, 0.25]);
+                assert_eq!(img.get_pixel(1, 0).0, [0.0, 0.0, 0.0]);
+            }
+            other => panic!("expected ImageRgb32F, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn buffer_too_small_for_dimensions_is_rejected() {
+        let result = dynamic_image_from_raw(color::ColorType::Rgb8, 4, 4, vec![0u8; 3]);
+        assert!(result.is_err());
+    }
+}
+
 pub(crate) fn image_dimensions_impl(path: &Path) -> ImageResult<(u32, u32)> {
     let format = image::ImageFormat::from_path(path)?;
 
@@ -166,6 +347,8 @@ pub(crate) fn save_buffer_impl(
     save_buffer_with_format_impl(path, buf, width, height, color, format)
 }
 
+/// Save with the default [`super::Limits`], the same protection [`load`] gets by default from
+/// [`load_inner`].
 #[allow(unused_variables)]
 // Most variables when no features are supported
 pub(crate) fn save_buffer_with_format_impl(
@@ -175,6 +358,38 @@ pub(crate) fn save_buffer_with_format_impl(
     height: u32,
     color: color::ColorType,
     format: ImageFormat,
+) -> ImageResult<()> {
+    save_buffer_with_limits_impl(path, buf, width, height, color, format, super::Limits::default())
+}
+
+/// Save `buf` to `path` in `format`, validating `width`/`height`/allocation size against `limits`
+/// before encoding instead of the default, permissive [`Limits`](super::Limits).
+///
+/// This is the save-side counterpart to configuring decode limits: useful for server-side code
+/// that encodes caller-supplied dimensions and wants the same guardrails decoding already has.
+#[allow(unused_variables)]
+// Most variables when no features are supported
+pub fn save_buffer_with_format_and_limits(
+    path: &Path,
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    color: color::ColorType,
+    format: ImageFormat,
+    limits: super::Limits,
+) -> ImageResult<()> {
+    save_buffer_with_limits_impl(path, buf, width, height, color, format, limits)
+}
+
+#[allow(unused_variables)]
+// Most variables when no features are supported
+fn encode_buffer_impl(
+    path: &Path,
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    color: color::ColorType,
+    format: ImageFormat,
 ) -> ImageResult<()> {
     let fout = &mut BufWriter::new(File::create(path)?);
 
@@ -210,8 +425,42 @@ pub(crate) fn save_buffer_with_format_impl(
         image::ImageFormat::Farbfeld => farbfeld::FarbfeldEncoder::new(fout).write_image(buf, width, height, color),
         #[cfg(feature = "avif-encoder")]
         image::ImageFormat::Avif => avif::AvifEncoder::new(fout).write_image(buf, width, height, color),
-        // #[cfg(feature = "hdr")]
-        // image::ImageFormat::Hdr => hdr::HdrEncoder::new(fout).encode(&[Rgb<f32>], width, height), // usize
+        #[cfg(feature = "hdr")]
+        image::ImageFormat::Hdr => match color {
+            color::ColorType::Rgb32F => {
+                let expected_len = (width as usize)
+                    .saturating_mul(height as usize)
+                    .saturating_mul(3 * std::mem::size_of::<f32>());
+                if buf.len() < expected_len {
+                    return Err(ImageError::Parameter(crate::error::ParameterError::from_kind(
+                        crate::error::ParameterErrorKind::DimensionMismatch)));
+                }
+                let data: Vec<color::Rgb<f32>> = buf[..expected_len]
+                    .chunks_exact(3 * std::mem::size_of::<f32>())
+                    .map(|p| {
+                        color::Rgb([
+                            f32::from_ne_bytes([p[0], p[1], p[2], p[3]]),
+                            f32::from_ne_bytes([p[4], p[5], p[6], p[7]]),
+                            f32::from_ne_bytes([p[8], p[9], p[10], p[11]]),
+                        ])
+                    })
+                    .collect();
+                hdr::HdrEncoder::new(fout).encode(&data, width as usize, height as usize)
+            }
+            color::ColorType::Rgb8 => {
+                let expected_len = (width as usize).saturating_mul(height as usize).saturating_mul(3);
+                if buf.len() < expected_len {
+                    return Err(ImageError::Parameter(crate::error::ParameterError::from_kind(
+                        crate::error::ParameterErrorKind::DimensionMismatch)));
+                }
+                let data: Vec<color::Rgb<f32>> = buf[..expected_len]
+                    .chunks_exact(3)
+                    .map(|p| color::Rgb([p[0] as f32 / 255.0, p[1] as f32 / 255.0, p[2] as f32 / 255.0]))
+                    .collect();
+                hdr::HdrEncoder::new(fout).encode(&data, width as usize, height as usize)
+            }
+            _ => Err(ImageError::Unsupported(ImageFormatHint::Exact(format).into())),
+        },
         #[cfg(feature = "bmp")]
         image::ImageFormat::Bmp => bmp::BmpEncoder::new(fout).write_image(buf, width, height, color),
         #[cfg(feature = "tiff")]
@@ -223,12 +472,53 @@ pub(crate) fn save_buffer_with_format_impl(
     }
 }
 
-static MAGIC_BYTES: [(&[u8], ImageFormat); 20] = [
+#[allow(unused_variables)]
+// Most variables when no features are supported
+pub(crate) fn save_buffer_with_limits_impl(
+    path: &Path,
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    color: color::ColorType,
+    format: ImageFormat,
+    limits: super::Limits,
+) -> ImageResult<()> {
+    check_limits(width, height, color, &limits)?;
+    encode_buffer_impl(path, buf, width, height, color, format)
+}
+
+fn check_limits(width: u32, height: u32, color: color::ColorType, limits: &super::Limits) -> ImageResult<()> {
+    if let Some(max_image_width) = limits.max_image_width {
+        if width > max_image_width {
+            return Err(ImageError::Limits(crate::error::LimitError::from_kind(
+                crate::error::LimitErrorKind::DimensionError)))
+        }
+    }
+    if let Some(max_image_height) = limits.max_image_height {
+        if height > max_image_height {
+            return Err(ImageError::Limits(crate::error::LimitError::from_kind(
+                crate::error::LimitErrorKind::DimensionError)))
+        }
+    }
+    if let Some(max_alloc) = limits.max_alloc {
+        let total_bytes = u64::from(width)
+            .checked_mul(u64::from(height))
+            .and_then(|pixels| pixels.checked_mul(u64::from(color.bytes_per_pixel())));
+        // An overflow here means the implied buffer is larger than any `max_alloc` we could be
+        // given, so treat it the same as exceeding the limit rather than letting it wrap.
+        if total_bytes.map_or(true, |total_bytes| total_bytes > max_alloc) {
+            return Err(ImageError::Limits(crate::error::LimitError::from_kind(
+                crate::error::LimitErrorKind::InsufficientMemory)))
+        }
+    }
+    Ok(())
+}
+
+static MAGIC_BYTES: [(&[u8], ImageFormat); 18] = [
     (b"\x89PNG\r\n\x1a\n", ImageFormat::Png),
     (&[0xff, 0xd8, 0xff], ImageFormat::Jpeg),
     (b"GIF89a", ImageFormat::Gif),
     (b"GIF87a", ImageFormat::Gif),
-    (b"RIFF", ImageFormat::WebP), // TODO: better magic byte detection, see https://github.com/image-rs/image/issues/660
     (b"MM\x00*", ImageFormat::Tiff),
     (b"II*\x00", ImageFormat::Tiff),
     (b"DDS ", ImageFormat::Dds),
@@ -243,9 +533,35 @@ static MAGIC_BYTES: [(&[u8], ImageFormat); 20] = [
     (b"P6", ImageFormat::Pnm),
     (b"P7", ImageFormat::Pnm),
     (b"farbfeld", ImageFormat::Farbfeld),
-    (b"\0\0\0 ftypavif", ImageFormat::Avif),
 ];
 
+/// Checks for a RIFF container whose form type is `WEBP`.
+///
+/// A bare `RIFF` prefix is not sufficient to identify WebP: `RIFF` is a generic container used
+/// by many formats (AVI, WAV, ...), so this additionally requires the form type at bytes 8..12
+/// to be `WEBP`.
+fn is_riff_webp(buffer: &[u8]) -> bool {
+    buffer.len() >= 12 && &buffer[0..4] == b"RIFF" && &buffer[8..12] == b"WEBP"
+}
+
+/// Checks for an ISO base media file format (ISO-BMFF) container carrying an AVIF brand.
+///
+/// Reads the 4-byte big-endian length of the leading box, confirms its type is `ftyp`, then
+/// scans the major brand and the compatible-brand list inside that box for `avif`/`avis`. This
+/// is more robust than matching one fixed-size byte string, since the `ftyp` box size and brand
+/// order vary between encoders.
+fn is_isobmff_avif(buffer: &[u8]) -> bool {
+    if buffer.len() < 16 || &buffer[4..8] != b"ftyp" {
+        return false;
+    }
+    let box_len = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+    if box_len < 16 || buffer.len() < box_len {
+        return false;
+    }
+    let is_avif_brand = |brand: &[u8]| brand == b"avif" || brand == b"avis";
+    is_avif_brand(&buffer[8..12]) || buffer[16..box_len].chunks_exact(4).any(is_avif_brand)
+}
+
 /// Guess image format from memory block
 ///
 /// Makes an educated guess about the image format based on the Magic Bytes at the beginning.
@@ -258,6 +574,26 @@ pub fn guess_format(buffer: &[u8]) -> ImageResult<ImageFormat> {
     }
 }
 
+/// Guess image format from a `BufRead` without consuming any of its bytes.
+///
+/// Peeks the leading bytes via [`BufRead::fill_buf`] and runs the same probes as
+/// [`guess_format_impl`] (and so, transitively, [`guess_format`]) over them, so the two stay
+/// consistent. Because this only peeks, `r` can be handed to [`load`] afterwards to decode the
+/// same stream.
+///
+/// The peeked byte count depends on how much of `r`'s internal buffer is already filled, so `r`
+/// must be backed by a buffer large enough to hold at least the longest signature this function
+/// checks, or detection of that format will fail.
+///
+/// [`load`]: fn.load.html
+pub fn guess_format_from_reader<R: BufRead>(r: &mut R) -> ImageResult<ImageFormat> {
+    let buffer = r.fill_buf()?;
+    match guess_format_impl(buffer) {
+        Some(format) => Ok(format),
+        None => Err(ImageError::Unsupported(ImageFormatHint::Unknown.into())),
+    }
+}
+
 pub(crate) fn guess_format_impl(buffer: &[u8]) -> Option<ImageFormat> {
     for &(signature, format) in &MAGIC_BYTES {
         if buffer.starts_with(signature) {
@@ -265,5 +601,75 @@ pub(crate) fn guess_format_impl(buffer: &[u8]) -> Option<ImageFormat> {
         }
     }
 
+    if is_riff_webp(buffer) {
+        return Some(ImageFormat::WebP);
+    }
+
+    if is_isobmff_avif(buffer) {
+        return Some(ImageFormat::Avif);
+    }
+
     None
 }
+
+#[cfg(test)]
+mod format_detection_tests {
+    use super::*;
+
+    #[test]
+    fn riff_webp_is_detected() {
+        let buf = b"RIFF\x00\x00\x00\x00WEBPVP8 ";
+        assert_eq!(guess_format_impl(buf), Some(ImageFormat::WebP));
+    }
+
+    #[test]
+    fn riff_non_webp_is_not_misdetected_as_webp() {
+        // A bare `RIFF` prefix (e.g. an AVI or WAV file) must not match WebP now that the form
+        // type is checked.
+        let buf = b"RIFF\x00\x00\x00\x00AVI LIST";
+        assert_eq!(guess_format_impl(buf), None);
+    }
+
+    #[test]
+    fn avif_major_brand_is_detected() {
+        let mut buf = vec![0u8, 0, 0, 20];
+        buf.extend_from_slice(b"ftyp");
+        buf.extend_from_slice(b"avif"); // major brand
+        buf.extend_from_slice(&[0, 0, 0, 0]); // minor version
+        buf.extend_from_slice(b"mif1"); // compatible brand
+        assert_eq!(guess_format_impl(&buf), Some(ImageFormat::Avif));
+    }
+
+    #[test]
+    fn avif_compatible_brand_list_is_scanned() {
+        // The major brand is something else entirely; `avis` only shows up in the
+        // compatible-brand list, which must still be scanned.
+        let mut buf = vec![0u8, 0, 0, 20];
+        buf.extend_from_slice(b"ftyp");
+        buf.extend_from_slice(b"mif1"); // major brand
+        buf.extend_from_slice(&[0, 0, 0, 0]); // minor version
+        buf.extend_from_slice(b"avis"); // compatible brand
+        assert_eq!(guess_format_impl(&buf), Some(ImageFormat::Avif));
+    }
+
+    #[test]
+    fn non_ftyp_isobmff_box_is_not_detected_as_avif() {
+        let mut buf = vec![0u8, 0, 0, 20];
+        buf.extend_from_slice(b"moov");
+        buf.extend_from_slice(b"avif");
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+        buf.extend_from_slice(b"mif1");
+        assert_eq!(guess_format_impl(&buf), None);
+    }
+
+    #[test]
+    fn short_or_truncated_buffers_are_rejected() {
+        assert!(!is_riff_webp(b"RIFF"));
+        assert!(!is_isobmff_avif(b"\x00\x00\x00\x18ftyp"));
+        // `box_len` claims more bytes than the buffer actually holds.
+        let mut buf = vec![0u8, 0, 0, 100];
+        buf.extend_from_slice(b"ftypavif");
+        buf.extend_from_slice(&[0, 0, 0, 0]); // minor version
+        assert!(!is_isobmff_avif(&buf));
+    }
+}